@@ -3,40 +3,61 @@
 # TMUX LOGGER - HIGH PERFORMANCE PANE CAPTURE
 # ==============================================================================
 # Purpose: Captures raw terminal output from a tmux pane.
-# Usage:   tmux_logger.sh [LOG_DIR] [PANE_ID]
+# Usage:   tmux_logger.sh [LOG_DIR] [PANE_ID] [TARGET] [SOCKET]
+#          tmux_logger.sh --resized
 # Arguments:
 #   LOG_DIR: (Optional) Path to store logs. Default: ~/.tmux/logs
 #   PANE_ID: (Optional) Unique identifier for the pane. Default: "default"
+#   TARGET:  (Optional) tmux pane to watch for resizes. Default: $TMUX_PANE
+#   SOCKET:  (Optional) tmux server socket, as tmux does not pass $TMUX to
+#            pipe-pane commands. Default: tmux's default server
+#   --resized: Run from tmux resize hooks; tells every running logger to
+#              record its pane's new size
+# Environment:
+#   ANSI2PNG: ansi2png binary that writes the log. Default: ansi2png on the
+#             PATH, else ~/.local/bin/ansi2png
 # ==============================================================================
 
+RUN_DIR="/dev/shm/tmux_${USER}/loggers"
+
+# Resize hook: each logger looks up its own pane size and skips unchanged ones
+if [[ "$1" == "--resized" ]]; then
+    for pid_file in "$RUN_DIR"/*.pid; do
+        [[ -f "$pid_file" ]] || continue
+        pid=$(cat "$pid_file")
+        # A logger killed outright leaves its file behind; never signal
+        # whatever process reused the PID
+        if [[ "$(ps -ww -o args= -p "$pid" 2>/dev/null)" == *" log-writer "* ]]; then
+            kill -USR1 "$pid" 2>/dev/null
+        else
+            rm -f "$pid_file"
+        fi
+    done
+    exit 0
+fi
+
 LOG_DIR="${1:-$HOME/.tmux/logs}"
 PANE_ID="${2:-default}"
+TARGET="${3:-$TMUX_PANE}"
+SOCKET="${4:-}"
 MAX_BYTES=10485760 # 10MB
+ANSI2PNG="${ANSI2PNG:-$(command -v ansi2png || echo "$HOME/.local/bin/ansi2png")}"
 
 # Ensure log directory exists
-mkdir -p "$LOG_DIR" || exit 1
+mkdir -p "$LOG_DIR" "$RUN_DIR" || exit 1
 
 START_TIME=$(date +%Y%m%d_%H%M%S)
 LOG_FILE="$LOG_DIR/${PANE_ID}_${START_TIME}.log"
 
-# Background rotation monitor
-# Checks every 60s if the log exceeds the size limit
-(
-    while true; do
-        sleep 60
-        if [[ -f "$LOG_FILE" ]]; then
-            size=$(wc -c < "$LOG_FILE")
-            if (( size > MAX_BYTES )); then
-                mv "$LOG_FILE" "$LOG_FILE.$(date +%s).bak"
-            fi
-        fi
-    done
-) &
-MONITOR_PID=$!
-
-# Ensure the background monitor dies when the tmux pane/pipe closes
-trap "kill $MONITOR_PID 2>/dev/null" EXIT
-
 # Raw capture of the pane stream (stdout + stderr)
-# 'cat' is used for maximum throughput to prevent tmux pipe stalls
-cat >> "$LOG_FILE"
\ No newline at end of file
+# A single writer owns the log, passing output straight through for maximum
+# throughput to prevent tmux pipe stalls. It inserts LogResize markers, on
+# SIGUSR1 from the resize hooks, and rotates the log at the size limit, only
+# between escape sequences and characters. Resize hooks find it through the
+# pid file it keeps in RUN_DIR, named after its own PID.
+ARGS=(log-writer "$LOG_FILE" --max-bytes "$MAX_BYTES")
+if [[ -n "$TARGET" ]]; then
+    ARGS+=(--target "$TARGET" --pid-dir "$RUN_DIR")
+    [[ -n "$SOCKET" ]] && ARGS+=(--socket "$SOCKET")
+fi
+exec "$ANSI2PNG" "${ARGS[@]}"
@@ -0,0 +1,71 @@
+#!/bin/bash
+# ==============================================================================
+# LINE EDITING FIXTURE RECORDER
+# ==============================================================================
+# Purpose: Records the raw output of a shell line editor in a 40x12 tmux pane,
+#          along with the screen tmux shows at the end, for the Grid replay
+#          tests in src/main.rs.
+# Usage:   record.sh NAME KEY...
+#          Keys are tmux send-keys names (Left, C-a, Tab); a leading @ sends
+#          the rest as literal text.
+# Outputs: NAME.log (pipe-pane bytes), NAME.screen (capture-pane text) and
+#          NAME.cursor (final cursor column and row)
+# Environment:
+#   FIXTURE_SHELL: Shell command to record, or "zsh" for zsh without user
+#                  config, with a right prompt and menu completion.
+#                  Default: bash with readline
+# ZLE sessions (not recorded yet, replayed as zle_* once they are):
+#   FIXTURE_SHELL=zsh record.sh zle_insert_delete @'echo hllo wrld' C-a M-f Right @e End Left Left BSpace
+#   FIXTURE_SHELL=zsh record.sh zle_history @'echo first' Enter @'echo second' Enter Up Up Down C-r @fir
+#   FIXTURE_SHELL=zsh record.sh zle_menu_complete @'ls report' Tab Tab Tab
+#   FIXTURE_SHELL=zsh record.sh zle_rprompt_wrap @'echo this line runs into the right prompt and wraps' C-a C-k
+#   FIXTURE_SHELL=zsh record.sh zle_multiline_kill @'for f in *; do' Enter @'echo $f' M-x @push-line-or-edit Enter C-u
+# ==============================================================================
+
+NAME="$1"; shift
+OUT_DIR="$(cd "$(dirname "$0")" && pwd)"
+WORK_DIR="$(mktemp -d)"
+SOCKET="fixture-$$"
+PS1_BASH='\[\e[1;32m\]pt@kali\[\e[0m\]:\[\e[34m\]\W\[\e[0m\]\$ '
+FIXTURE_SHELL="${FIXTURE_SHELL:-HISTFILE=/dev/null INPUTRC=/dev/null PS1='$PS1_BASH' exec bash --norc --noprofile}"
+
+if [[ "$FIXTURE_SHELL" == zsh ]]; then
+    mkdir -p "$WORK_DIR/zdotdir"
+    cat > "$WORK_DIR/zdotdir/.zshrc" <<'EOF'
+PROMPT='%B%F{green}pt@kali%f%b:%F{blue}%1~%f%# '
+RPROMPT='%F{yellow}[%?]%f'
+HISTFILE=/dev/null
+zmodload zsh/complist
+autoload -Uz compinit && compinit -u -D
+zstyle ':completion:*' menu select
+EOF
+    FIXTURE_SHELL="ZDOTDIR='$WORK_DIR/zdotdir' exec zsh"
+fi
+
+# A few files for completion to find
+mkdir -p "$WORK_DIR/work"
+touch "$WORK_DIR/work/report_alpha.txt" "$WORK_DIR/work/report_beta.txt" "$WORK_DIR/work/notes.md"
+echo 'set -g default-terminal tmux-256color' > "$WORK_DIR/tmux.conf"
+
+tmux() { env -u TMUX tmux -L "$SOCKET" "$@"; }
+
+# The shell starts after a delay so pipe-pane sees its first prompt
+tmux -f "$WORK_DIR/tmux.conf" new-session -d -s fixture -x 40 -y 12 \
+    "sleep 1; cd '$WORK_DIR/work'; $FIXTURE_SHELL"
+tmux pipe-pane -t fixture "cat > '$OUT_DIR/$NAME.log'"
+sleep 1.8
+
+for key in "$@"; do
+    if [[ "$key" == @* ]]; then
+        tmux send-keys -t fixture -l "${key#@}"
+    else
+        tmux send-keys -t fixture "$key"
+    fi
+    sleep 0.3
+done
+sleep 0.5
+
+tmux capture-pane -p -t fixture > "$OUT_DIR/$NAME.screen"
+tmux display-message -p -t fixture '#{cursor_x} #{cursor_y}' > "$OUT_DIR/$NAME.cursor"
+tmux kill-server
+rm -rf "$WORK_DIR"